@@ -3,6 +3,8 @@ use crate::endpoint::Endpoint;
 use acme_common::crypto::X509Certificate;
 use acme_common::error::Error;
 use attohttpc::{charsets, header, Response, Session};
+use log::warn;
+use rand::Rng;
 use std::fs::File;
 use std::io::prelude::*;
 use std::{thread, time};
@@ -12,6 +14,10 @@ pub const CONTENT_TYPE_JSON: &str = "application/json";
 pub const CONTENT_TYPE_PEM: &str = "application/pem-certificate-chain";
 pub const HEADER_NONCE: &str = "Replay-Nonce";
 pub const HEADER_LOCATION: &str = "Location";
+pub const HEADER_RETRY_AFTER: &str = "Retry-After";
+
+// Upper bound on a single `Retry-After` or backoff sleep.
+const MAX_WAIT_SEC: u64 = 3_600;
 
 fn is_nonce(data: &str) -> bool {
     !data.is_empty()
@@ -20,10 +26,10 @@ fn is_nonce(data: &str) -> bool {
             .all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_')
 }
 
-fn new_nonce(endpoint: &mut Endpoint, root_certs: &[String]) -> Result<(), Error> {
+fn new_nonce(endpoint: &mut Endpoint, trust: &TrustConfig) -> Result<(), Error> {
     rate_limit(endpoint);
     let url = endpoint.dir.new_nonce.clone();
-    let _ = get(endpoint, root_certs, &url)?;
+    let _ = get(endpoint, trust, &url)?;
     Ok(())
 }
 
@@ -52,6 +58,32 @@ fn rate_limit(endpoint: &mut Endpoint) {
     endpoint.rl.block_until_allowed();
 }
 
+// Parses a `Retry-After` value (seconds or an RFC 1123 HTTP-date) into a duration.
+fn parse_retry_after(value: &str) -> Option<time::Duration> {
+    let wait = if !value.is_empty() && value.bytes().all(|c| c.is_ascii_digit()) {
+        time::Duration::from_secs(value.parse().ok()?)
+    } else {
+        let date = httpdate::parse_http_date(value).ok()?;
+        date.duration_since(time::SystemTime::now()).unwrap_or_default()
+    };
+    Some(std::cmp::min(wait, time::Duration::from_secs(MAX_WAIT_SEC)))
+}
+
+fn get_retry_after(response: &Response) -> Option<time::Duration> {
+    let header_value = response.headers().get(HEADER_RETRY_AFTER)?;
+    let value = header_to_string(header_value).ok()?;
+    parse_retry_after(&value)
+}
+
+// Capped exponential backoff with jitter, for when there is no `Retry-After` header.
+fn get_backoff_wait(attempt: u32) -> time::Duration {
+    let base = crate::DEFAULT_HTTP_FAIL_WAIT_SEC;
+    let exp = base.checked_shl(attempt).unwrap_or(u64::MAX);
+    let capped = std::cmp::min(exp, MAX_WAIT_SEC);
+    let jitter = rand::thread_rng().gen_range(0..=base);
+    time::Duration::from_secs(capped) + time::Duration::from_secs(jitter)
+}
+
 pub fn header_to_string(header_value: &header::HeaderValue) -> Result<String, Error> {
     let s = header_value
         .to_str()
@@ -59,7 +91,48 @@ pub fn header_to_string(header_value: &header::HeaderValue) -> Result<String, Er
     Ok(s.to_string())
 }
 
-fn get_session(root_certs: &[String]) -> Result<Session, Error> {
+// Loads the OS native trust store into `session`, skipping anchors that fail to parse.
+fn add_native_root_certs(session: &mut Session) -> Result<usize, Error> {
+    let result = rustls_native_certs::load_native_certs();
+    let mut loaded = 0;
+    for cert in result.certs {
+        match X509Certificate::from_der_native(cert.as_ref()) {
+            Ok(crt) => {
+                session.add_root_certificate(crt);
+                loaded += 1;
+            }
+            Err(e) => {
+                warn!("Unable to parse a native root certificate: {}", e);
+            }
+        }
+    }
+    for err in result.errors {
+        warn!("Unable to load a native root certificate: {}", err);
+    }
+    Ok(loaded)
+}
+
+/// Root certificates `get_session` should trust: explicit PEM files, the OS native
+/// trust store, and, for [`EndpointProfile::Test`], a throwaway root's PEM, used as the
+/// only anchor when set.
+#[derive(Default, Clone)]
+pub struct TrustConfig {
+    pub root_certs: Vec<String>,
+    pub use_native_root_certs: bool,
+    pub test_root_pem: Option<Vec<u8>>,
+}
+
+impl TrustConfig {
+    pub fn new(root_certs: Vec<String>, use_native_root_certs: bool) -> Self {
+        TrustConfig {
+            root_certs,
+            use_native_root_certs,
+            test_root_pem: None,
+        }
+    }
+}
+
+fn get_session(trust: &TrustConfig) -> Result<Session, Error> {
     let useragent = format!(
         "{}/{} ({}) {}",
         crate::APP_NAME,
@@ -72,17 +145,26 @@ fn get_session(root_certs: &[String]) -> Result<Session, Error> {
     session.default_charset(Some(charsets::UTF_8));
     session.try_header(header::ACCEPT_LANGUAGE, "en-US,en;q=0.5")?;
     session.try_header(header::USER_AGENT, &useragent)?;
-    for crt_file in root_certs.iter() {
+    if let Some(pem) = &trust.test_root_pem {
+        let crt = X509Certificate::from_pem_native(pem)?;
+        session.add_root_certificate(crt);
+        return Ok(session);
+    }
+    for crt_file in trust.root_certs.iter() {
         let mut buff = Vec::new();
         File::open(crt_file)?.read_to_end(&mut buff)?;
         let crt = X509Certificate::from_pem_native(&buff)?;
         session.add_root_certificate(crt);
     }
+    if trust.use_native_root_certs {
+        let nb = add_native_root_certs(&mut session)?;
+        log::trace!("{} native root certificate(s) loaded", nb);
+    }
     Ok(session)
 }
 
-pub fn get(endpoint: &mut Endpoint, root_certs: &[String], url: &str) -> Result<Response, Error> {
-    let mut session = get_session(root_certs)?;
+pub fn get(endpoint: &mut Endpoint, trust: &TrustConfig, url: &str) -> Result<Response, Error> {
+    let mut session = get_session(trust)?;
     session.try_header(header::ACCEPT, CONTENT_TYPE_JSON)?;
     rate_limit(endpoint);
     let response = session.get(url).send()?;
@@ -93,7 +175,7 @@ pub fn get(endpoint: &mut Endpoint, root_certs: &[String], url: &str) -> Result<
 
 pub fn post<F>(
     endpoint: &mut Endpoint,
-    root_certs: &[String],
+    trust: &TrustConfig,
     url: &str,
     data_builder: &F,
     content_type: &str,
@@ -102,38 +184,45 @@ pub fn post<F>(
 where
     F: Fn(&str, &str) -> Result<String, Error>,
 {
-    let mut session = get_session(root_certs)?;
+    let mut session = get_session(trust)?;
     session.try_header(header::ACCEPT, accept)?;
     session.try_header(header::CONTENT_TYPE, content_type)?;
     if endpoint.nonce.is_none() {
-        let _ = new_nonce(endpoint, root_certs);
+        let _ = new_nonce(endpoint, trust);
     }
-    for _ in 0..crate::DEFAULT_HTTP_FAIL_NB_RETRY {
+    for attempt in 0..crate::DEFAULT_HTTP_FAIL_NB_RETRY {
         let nonce = &endpoint.nonce.clone().unwrap();
         let body = data_builder(&nonce, url)?;
         rate_limit(endpoint);
         let response = session.post(url).text(&body).send()?;
         update_nonce(endpoint, &response)?;
+        let retry_after = get_retry_after(&response);
         match check_status(&response) {
             Ok(_) => {
                 return Ok(response);
             }
             Err(e) => {
-                let api_err = response.json::<HttpApiError>()?;
-                let acme_err = api_err.get_acme_type();
-                if !acme_err.is_recoverable() {
+                // A 429 is recoverable on its own, even when the body is not (or is not)
+                // a well-formed `HttpApiError` (e.g. a CDN-generated rate-limit page).
+                let recoverable = response.status().as_u16() == 429
+                    || response
+                        .json::<HttpApiError>()
+                        .map(|api_err| api_err.get_acme_type().is_recoverable())
+                        .unwrap_or(false);
+                if !recoverable {
                     return Err(e);
                 }
             }
         }
-        thread::sleep(time::Duration::from_secs(crate::DEFAULT_HTTP_FAIL_WAIT_SEC));
+        let wait = retry_after.unwrap_or_else(|| get_backoff_wait(attempt));
+        thread::sleep(wait);
     }
     Err("Too much errors, will not retry".into())
 }
 
 pub fn post_jose<F>(
     endpoint: &mut Endpoint,
-    root_certs: &[String],
+    trust: &TrustConfig,
     url: &str,
     data_builder: &F,
 ) -> Result<Response, Error>
@@ -142,7 +231,7 @@ where
 {
     post(
         endpoint,
-        root_certs,
+        trust,
         url,
         data_builder,
         CONTENT_TYPE_JOSE,
@@ -150,9 +239,110 @@ where
     )
 }
 
+/// Named ACME directory profiles. `Staging` swaps in Let's Encrypt's staging
+/// directory; `Production` and `Test` leave `endpoint.dir` untouched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EndpointProfile {
+    Production,
+    Staging,
+    Test,
+}
+
+impl Default for EndpointProfile {
+    fn default() -> Self {
+        EndpointProfile::Production
+    }
+}
+
+const STAGING_NEW_NONCE: &str = "https://acme-staging-v02.api.letsencrypt.org/acme/new-nonce";
+const STAGING_NEW_ACCOUNT: &str = "https://acme-staging-v02.api.letsencrypt.org/acme/new-acct";
+const STAGING_NEW_ORDER: &str = "https://acme-staging-v02.api.letsencrypt.org/acme/new-order";
+
+/// Points `endpoint.dir` at the directory URLs for `profile`.
+pub fn apply_endpoint_profile(endpoint: &mut Endpoint, profile: EndpointProfile) {
+    if profile == EndpointProfile::Staging {
+        endpoint.dir.new_nonce = STAGING_NEW_NONCE.to_string();
+        endpoint.dir.new_account = STAGING_NEW_ACCOUNT.to_string();
+        endpoint.dir.new_order = STAGING_NEW_ORDER.to_string();
+    }
+}
+
+/// Generates a throwaway self-signed root and a leaf certificate signed by it, for
+/// [`EndpointProfile::Test`].
+pub fn generate_test_root_and_leaf(common_name: &str) -> Result<(Vec<u8>, Vec<u8>), Error> {
+    let root_key = acme_common::crypto::KeyPair::from_algo(acme_common::crypto::KeyType::EcdsaP256)?;
+    let root_crt = X509Certificate::new_self_signed_root(&root_key, "ACMED test root")?;
+    let leaf_key = acme_common::crypto::KeyPair::from_algo(acme_common::crypto::KeyType::EcdsaP256)?;
+    let leaf_crt =
+        X509Certificate::new_signed_by(&leaf_key, common_name, &root_crt, &root_key)?;
+    Ok((root_crt.to_pem()?, leaf_crt.to_pem()?))
+}
+
 #[cfg(test)]
 mod tests {
-    use super::is_nonce;
+    use super::{
+        generate_test_root_and_leaf, get_backoff_wait, is_nonce, parse_retry_after, TrustConfig,
+        MAX_WAIT_SEC,
+    };
+    use acme_common::crypto::X509Certificate;
+    use std::time::Duration;
+
+    // `apply_endpoint_profile` takes a `&mut Endpoint`, not part of this crate, so it
+    // cannot be unit-tested from this module.
+
+    #[test]
+    fn test_trust_config_new_enables_native_root_certs() {
+        let trust = TrustConfig::new(vec!["/etc/acmed/root.pem".to_string()], true);
+        assert!(trust.use_native_root_certs);
+        assert_eq!(trust.root_certs, vec!["/etc/acmed/root.pem".to_string()]);
+        assert!(trust.test_root_pem.is_none());
+    }
+
+    #[test]
+    fn test_trust_config_new_can_disable_native_root_certs() {
+        let trust = TrustConfig::new(vec!["/etc/acmed/root.pem".to_string()], false);
+        assert!(!trust.use_native_root_certs);
+        assert_eq!(trust.root_certs, vec!["/etc/acmed/root.pem".to_string()]);
+    }
+
+    #[test]
+    fn test_trust_config_default_disables_native_root_certs() {
+        let trust = TrustConfig::default();
+        assert!(!trust.use_native_root_certs);
+    }
+
+    #[test]
+    fn test_parse_retry_after_digits() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+    }
+
+    #[test]
+    fn test_parse_retry_after_http_date() {
+        let future = httpdate::fmt_http_date(std::time::SystemTime::now() + Duration::from_secs(60));
+        let wait = parse_retry_after(&future).expect("a valid HTTP-date must parse");
+        assert!(wait.as_secs() <= 60);
+    }
+
+    #[test]
+    fn test_parse_retry_after_capped() {
+        let wait = parse_retry_after(&(MAX_WAIT_SEC * 10).to_string()).unwrap();
+        assert_eq!(wait, Duration::from_secs(MAX_WAIT_SEC));
+    }
+
+    #[test]
+    fn test_parse_retry_after_invalid() {
+        assert!(parse_retry_after("not-a-valid-value").is_none());
+    }
+
+    #[test]
+    fn test_backoff_wait_grows_and_is_capped() {
+        let base = crate::DEFAULT_HTTP_FAIL_WAIT_SEC;
+        for attempt in 0..8 {
+            let wait = get_backoff_wait(attempt);
+            assert!(wait.as_secs() >= base.min(MAX_WAIT_SEC));
+            assert!(wait.as_secs() <= MAX_WAIT_SEC + base);
+        }
+    }
 
     #[test]
     fn test_nonce_valid() {
@@ -185,4 +375,12 @@ mod tests {
             assert!(!is_nonce(n));
         }
     }
+
+    #[test]
+    fn test_generate_test_root_and_leaf() {
+        let (root_pem, leaf_pem) = generate_test_root_and_leaf("acmed.test")
+            .expect("generating a throwaway root and leaf must not fail");
+        X509Certificate::from_pem(&root_pem).expect("the root must be a valid PEM certificate");
+        X509Certificate::from_pem(&leaf_pem).expect("the leaf must be a valid PEM certificate");
+    }
 }
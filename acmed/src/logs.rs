@@ -1,12 +1,15 @@
 use crate::errors::Error;
 use env_logger::Builder;
-use log::LevelFilter;
-use syslog::Facility;
+use log::{LevelFilter, Log, Metadata, Record};
+use syslog::{BasicLogger, Facility, Formatter3164};
+use systemd_journal_logger::JournalLog;
+use std::sync::{OnceLock, RwLock};
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum LogSystem {
     SysLog,
     StdErr,
+    Journald,
 }
 
 fn get_loglevel(log_level: Option<&str>) -> Result<LevelFilter, Error> {
@@ -26,24 +29,110 @@ fn get_loglevel(log_level: Option<&str>) -> Result<LevelFilter, Error> {
     Ok(level)
 }
 
-fn set_log_syslog(log_level: LevelFilter) -> Result<(), Error> {
-    syslog::init(Facility::LOG_DAEMON, log_level, Some(crate::APP_NAME))?;
-    Ok(())
+fn get_facility(facility: Option<&str>) -> Result<Facility, Error> {
+    let facility = match facility {
+        Some(v) => match v {
+            "LOG_KERN" => Facility::LOG_KERN,
+            "LOG_USER" => Facility::LOG_USER,
+            "LOG_MAIL" => Facility::LOG_MAIL,
+            "LOG_DAEMON" => Facility::LOG_DAEMON,
+            "LOG_AUTH" => Facility::LOG_AUTH,
+            "LOG_SYSLOG" => Facility::LOG_SYSLOG,
+            "LOG_LPR" => Facility::LOG_LPR,
+            "LOG_NEWS" => Facility::LOG_NEWS,
+            "LOG_UUCP" => Facility::LOG_UUCP,
+            "LOG_CRON" => Facility::LOG_CRON,
+            "LOG_AUTHPRIV" => Facility::LOG_AUTHPRIV,
+            "LOG_FTP" => Facility::LOG_FTP,
+            "LOG_LOCAL0" => Facility::LOG_LOCAL0,
+            "LOG_LOCAL1" => Facility::LOG_LOCAL1,
+            "LOG_LOCAL2" => Facility::LOG_LOCAL2,
+            "LOG_LOCAL3" => Facility::LOG_LOCAL3,
+            "LOG_LOCAL4" => Facility::LOG_LOCAL4,
+            "LOG_LOCAL5" => Facility::LOG_LOCAL5,
+            "LOG_LOCAL6" => Facility::LOG_LOCAL6,
+            "LOG_LOCAL7" => Facility::LOG_LOCAL7,
+            _ => {
+                return Err(Error::new(&format!("{}: invalid syslog facility", v)));
+            }
+        },
+        None => crate::DEFAULT_LOG_FACILITY,
+    };
+    Ok(facility)
 }
 
-fn set_log_stderr(log_level: LevelFilter) -> Result<(), Error> {
+// A no-op stand-in installed before the first real log target is chosen.
+struct NopLogger;
+
+impl Log for NopLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        false
+    }
+    fn log(&self, _record: &Record) {}
+    fn flush(&self) {}
+}
+
+// `log::set_logger` can only succeed once per process, so this is installed exactly
+// once; reloading a target only swaps what it delegates to, behind a lock.
+struct ReloadableLogger {
+    inner: RwLock<Box<dyn Log>>,
+}
+
+impl Log for ReloadableLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.read().unwrap().enabled(metadata)
+    }
+    fn log(&self, record: &Record) {
+        self.inner.read().unwrap().log(record)
+    }
+    fn flush(&self) {
+        self.inner.read().unwrap().flush()
+    }
+}
+
+static DISPATCHER: OnceLock<&'static ReloadableLogger> = OnceLock::new();
+
+fn dispatcher() -> &'static ReloadableLogger {
+    *DISPATCHER.get_or_init(|| {
+        let logger: &'static ReloadableLogger = Box::leak(Box::new(ReloadableLogger {
+            inner: RwLock::new(Box::new(NopLogger)),
+        }));
+        log::set_logger(logger).expect("the global logger dispatcher can only be installed once");
+        logger
+    })
+}
+
+fn build_syslog_logger(facility: Facility) -> Result<Box<dyn Log>, Error> {
+    let formatter = Formatter3164 {
+        facility,
+        hostname: None,
+        process: crate::APP_NAME.to_string(),
+        pid: std::process::id() as i32,
+    };
+    let logger = syslog::unix(formatter)?;
+    Ok(Box::new(BasicLogger::new(logger)))
+}
+
+fn build_stderr_logger(log_level: LevelFilter) -> Box<dyn Log> {
     let mut builder = Builder::from_env("ACMED_LOG_LEVEL");
     builder.filter_level(log_level);
-    builder.init();
-    Ok(())
+    Box::new(builder.build())
+}
+
+fn build_journald_logger() -> Result<Box<dyn Log>, Error> {
+    let logger = JournalLog::new()?.with_syslog_identifier(crate::APP_NAME.to_string());
+    Ok(Box::new(logger))
 }
 
 pub fn set_log_system(
     log_level: Option<&str>,
+    facility: Option<&str>,
     has_syslog: bool,
+    has_journald: bool,
     has_stderr: bool,
-) -> Result<(LogSystem, LevelFilter), Error> {
+) -> Result<(LogSystem, LevelFilter, Facility), Error> {
     let log_level = get_loglevel(log_level)?;
+    let facility = get_facility(facility)?;
     let mut logtype = crate::DEFAULT_LOG_SYSTEM;
     if has_stderr {
         logtype = LogSystem::StdErr;
@@ -51,11 +140,17 @@ pub fn set_log_system(
     if has_syslog {
         logtype = LogSystem::SysLog;
     }
-    match logtype {
-        LogSystem::SysLog => set_log_syslog(log_level)?,
-        LogSystem::StdErr => set_log_stderr(log_level)?,
+    if has_journald {
+        logtype = LogSystem::Journald;
+    }
+    let logger = match logtype {
+        LogSystem::SysLog => build_syslog_logger(facility)?,
+        LogSystem::StdErr => build_stderr_logger(log_level),
+        LogSystem::Journald => build_journald_logger()?,
     };
-    Ok((logtype, log_level))
+    *dispatcher().inner.write().unwrap() = logger;
+    log::set_max_level(log_level);
+    Ok((logtype, log_level, facility))
 }
 
 #[cfg(test)]
@@ -64,15 +159,21 @@ mod tests {
 
     #[test]
     fn test_invalid_level() {
-        let ret = set_log_system(Some("invalid"), false, false);
+        let ret = set_log_system(Some("invalid"), None, false, false, false);
+        assert!(ret.is_err());
+    }
+
+    #[test]
+    fn test_invalid_facility() {
+        let ret = set_log_system(None, Some("LOG_NOPE"), false, false, false);
         assert!(ret.is_err());
     }
 
     #[test]
     fn test_default_values() {
-        let ret = set_log_system(None, false, false);
+        let ret = set_log_system(None, None, false, false, false);
         assert!(ret.is_ok());
-        let (logtype, log_level) = ret.unwrap();
+        let (logtype, log_level, _facility) = ret.unwrap();
         assert_eq!(logtype, crate::DEFAULT_LOG_SYSTEM);
         assert_eq!(log_level, crate::DEFAULT_LOG_LEVEL);
     }
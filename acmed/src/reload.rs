@@ -0,0 +1,187 @@
+use crate::config::Config;
+use crate::logs::set_log_system;
+use acme_common::error::Error;
+use log::{error, info};
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+#[cfg(target_family = "unix")]
+use signal_hook::{consts::SIGHUP, iterator::Signals};
+
+// Set by a SIGHUP or a config mtime change; `run` checks it between passes instead of
+// reloading from inside the signal handler.
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+pub fn request_reload() {
+    RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+fn take_reload_request() -> bool {
+    RELOAD_REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+/// Turns every `SIGHUP` received by the process into a reload request.
+#[cfg(target_family = "unix")]
+fn watch_sighup() -> Result<(), Error> {
+    let mut signals = Signals::new([SIGHUP])?;
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            info!("SIGHUP received, the configuration will be reloaded");
+            request_reload();
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(target_family = "unix"))]
+fn watch_sighup() -> Result<(), Error> {
+    Ok(())
+}
+
+/// Polls the configuration file's mtime every `interval` and requests a reload on change.
+fn watch_config_mtime(config_path: impl AsRef<Path> + Send + 'static, interval: Duration) {
+    thread::spawn(move || {
+        let mut last_mtime = fs::metadata(&config_path).and_then(|m| m.modified()).ok();
+        loop {
+            thread::sleep(interval);
+            let mtime = match fs::metadata(&config_path).and_then(|m| m.modified()) {
+                Ok(m) => m,
+                Err(_) => continue,
+            };
+            if last_mtime != Some(mtime) {
+                last_mtime = Some(mtime);
+                info!("configuration file change detected, a reload will be applied");
+                request_reload();
+            }
+        }
+    });
+}
+
+// Split out of `diff_certificates` as a pure function so it can be unit-tested without
+// an actual `Config`.
+fn diff_certificate_names(old_names: &[String], new_names: &[String]) -> (Vec<String>, Vec<String>) {
+    let old_names: HashSet<&String> = old_names.iter().collect();
+    let new_names: HashSet<&String> = new_names.iter().collect();
+    let added = new_names.difference(&old_names).map(|s| s.to_string()).collect();
+    let removed = old_names.difference(&new_names).map(|s| s.to_string()).collect();
+    (added, removed)
+}
+
+/// Returns the certificate names added and removed between `old` and `new`.
+fn diff_certificates(old: &Config, new: &Config) -> (Vec<String>, Vec<String>) {
+    diff_certificate_names(&old.certificate_names(), &new.certificate_names())
+}
+
+/// Re-reads the configuration file and, if it parses, diffs its certificate set
+/// against `running_config`, swaps the new configuration in, and calls
+/// `on_certificates_changed` with the names added and removed. A config that fails to
+/// parse is rejected and the previous running configuration is left untouched.
+pub fn apply_reload<F>(
+    config_path: &str,
+    running_config: &Arc<RwLock<Config>>,
+    on_certificates_changed: F,
+) -> Result<(), Error>
+where
+    F: FnOnce(&[String], &[String]),
+{
+    let new_config = Config::from_file(config_path).map_err(|e| {
+        error!("{}: unable to reload the configuration: {}", config_path, e);
+        e
+    })?;
+
+    let (log_system, log_level, facility) = set_log_system(
+        new_config.log_level(),
+        new_config.log_facility(),
+        new_config.has_syslog(),
+        new_config.has_journald(),
+        new_config.has_stderr(),
+    )?;
+    info!(
+        "configuration reloaded: log system set to {:?} at level {} (facility {:?})",
+        log_system, log_level, facility
+    );
+
+    let mut guard = running_config
+        .write()
+        .map_err(|_| Error::from("running configuration lock was poisoned"))?;
+    let (added, removed) = diff_certificates(&guard, &new_config);
+    for name in &added {
+        info!("{}: certificate added by the reloaded configuration", name);
+    }
+    for name in &removed {
+        info!("{}: certificate removed by the reloaded configuration", name);
+    }
+    *guard = new_config;
+    drop(guard);
+
+    on_certificates_changed(&added, &removed);
+    Ok(())
+}
+
+/// Watches for `SIGHUP` and, if `mtime_poll_interval` is set, for configuration file
+/// changes, applying a reload through [`apply_reload`] each time one is requested. The
+/// single entry point the main loop should run (in its own thread) at startup; never
+/// returns.
+pub fn run<F>(
+    config_path: String,
+    running_config: Arc<RwLock<Config>>,
+    mtime_poll_interval: Option<Duration>,
+    mut on_certificates_changed: F,
+) -> Result<(), Error>
+where
+    F: FnMut(&[String], &[String]) + Send,
+{
+    watch_sighup()?;
+    if let Some(interval) = mtime_poll_interval {
+        watch_config_mtime(config_path.clone(), interval);
+    }
+    loop {
+        if take_reload_request() {
+            let result = apply_reload(&config_path, &running_config, |added, removed| {
+                on_certificates_changed(added, removed)
+            });
+            if let Err(e) = result {
+                error!(
+                    "configuration reload failed, the previous configuration is kept: {}",
+                    e
+                );
+            }
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::diff_certificate_names;
+
+    fn names(v: &[&str]) -> Vec<String> {
+        v.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_diff_certificate_names_added_only() {
+        let (added, removed) = diff_certificate_names(&names(&["a"]), &names(&["a", "b"]));
+        assert_eq!(added, names(&["b"]));
+        assert!(removed.is_empty());
+    }
+
+    #[test]
+    fn test_diff_certificate_names_removed_only() {
+        let (added, removed) = diff_certificate_names(&names(&["a", "b"]), &names(&["a"]));
+        assert!(added.is_empty());
+        assert_eq!(removed, names(&["b"]));
+    }
+
+    #[test]
+    fn test_diff_certificate_names_added_and_removed() {
+        let (added, removed) = diff_certificate_names(&names(&["a", "b"]), &names(&["b", "c"]));
+        assert_eq!(added, names(&["c"]));
+        assert_eq!(removed, names(&["a"]));
+    }
+}
@@ -1,3 +1,4 @@
+use super::Storage;
 use crate::certificate::Certificate;
 use crate::hooks::{self, FileStorageHookData, HookEnvData, HookType};
 use acme_common::b64_encode;
@@ -173,44 +174,6 @@ fn write_file(cert: &Certificate, file_type: FileType, data: &[u8]) -> Result<()
     Ok(())
 }
 
-pub fn get_account_keypair(cert: &Certificate) -> Result<KeyPair, Error> {
-    let path = get_file_path(cert, FileType::AccountPrivateKey)?;
-    let raw_key = read_file(cert, &path)?;
-    let key = KeyPair::from_pem(&raw_key)?;
-    Ok(key)
-}
-
-pub fn set_account_keypair(cert: &Certificate, key_pair: &KeyPair) -> Result<(), Error> {
-    let pem_pub_key = key_pair.private_key_to_pem()?;
-    let pem_priv_key = key_pair.public_key_to_pem()?;
-    write_file(cert, FileType::AccountPublicKey, &pem_priv_key)?;
-    write_file(cert, FileType::AccountPrivateKey, &pem_pub_key)?;
-    Ok(())
-}
-
-pub fn get_keypair(cert: &Certificate) -> Result<KeyPair, Error> {
-    let path = get_file_path(cert, FileType::PrivateKey)?;
-    let raw_key = read_file(cert, &path)?;
-    let key = KeyPair::from_pem(&raw_key)?;
-    Ok(key)
-}
-
-pub fn set_keypair(cert: &Certificate, key_pair: &KeyPair) -> Result<(), Error> {
-    let data = key_pair.private_key_to_pem()?;
-    write_file(cert, FileType::PrivateKey, &data)
-}
-
-pub fn get_certificate(cert: &Certificate) -> Result<X509Certificate, Error> {
-    let path = get_file_path(cert, FileType::Certificate)?;
-    let raw_crt = read_file(cert, &path)?;
-    let crt = X509Certificate::from_pem(&raw_crt)?;
-    Ok(crt)
-}
-
-pub fn write_certificate(cert: &Certificate, data: &[u8]) -> Result<(), Error> {
-    write_file(cert, FileType::Certificate, data)
-}
-
 fn check_files(cert: &Certificate, file_types: &[FileType]) -> bool {
     for t in file_types.to_vec() {
         let path = match get_file_path(cert, t) {
@@ -227,12 +190,55 @@ fn check_files(cert: &Certificate, file_types: &[FileType]) -> bool {
     true
 }
 
-pub fn account_files_exists(cert: &Certificate) -> bool {
-    let file_types = vec![FileType::AccountPrivateKey, FileType::AccountPublicKey];
-    check_files(cert, &file_types)
-}
+/// Stores account keys, certificate keys and certificates as PEM files.
+pub struct FileStorage;
 
-pub fn certificate_files_exists(cert: &Certificate) -> bool {
-    let file_types = vec![FileType::PrivateKey, FileType::Certificate];
-    check_files(cert, &file_types)
+impl Storage for FileStorage {
+    fn get_account_keypair(&self, cert: &Certificate) -> Result<KeyPair, Error> {
+        let path = get_file_path(cert, FileType::AccountPrivateKey)?;
+        let raw_key = read_file(cert, &path)?;
+        let key = KeyPair::from_pem(&raw_key)?;
+        Ok(key)
+    }
+
+    fn set_account_keypair(&self, cert: &Certificate, key_pair: &KeyPair) -> Result<(), Error> {
+        let pem_pub_key = key_pair.private_key_to_pem()?;
+        let pem_priv_key = key_pair.public_key_to_pem()?;
+        write_file(cert, FileType::AccountPublicKey, &pem_priv_key)?;
+        write_file(cert, FileType::AccountPrivateKey, &pem_pub_key)?;
+        Ok(())
+    }
+
+    fn get_keypair(&self, cert: &Certificate) -> Result<KeyPair, Error> {
+        let path = get_file_path(cert, FileType::PrivateKey)?;
+        let raw_key = read_file(cert, &path)?;
+        let key = KeyPair::from_pem(&raw_key)?;
+        Ok(key)
+    }
+
+    fn set_keypair(&self, cert: &Certificate, key_pair: &KeyPair) -> Result<(), Error> {
+        let data = key_pair.private_key_to_pem()?;
+        write_file(cert, FileType::PrivateKey, &data)
+    }
+
+    fn get_certificate(&self, cert: &Certificate) -> Result<X509Certificate, Error> {
+        let path = get_file_path(cert, FileType::Certificate)?;
+        let raw_crt = read_file(cert, &path)?;
+        let crt = X509Certificate::from_pem(&raw_crt)?;
+        Ok(crt)
+    }
+
+    fn write_certificate(&self, cert: &Certificate, data: &[u8]) -> Result<(), Error> {
+        write_file(cert, FileType::Certificate, data)
+    }
+
+    fn account_files_exists(&self, cert: &Certificate) -> bool {
+        let file_types = vec![FileType::AccountPrivateKey, FileType::AccountPublicKey];
+        check_files(cert, &file_types)
+    }
+
+    fn certificate_files_exists(&self, cert: &Certificate) -> bool {
+        let file_types = vec![FileType::PrivateKey, FileType::Certificate];
+        check_files(cert, &file_types)
+    }
 }
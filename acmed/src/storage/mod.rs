@@ -0,0 +1,37 @@
+mod file;
+mod sqlite;
+
+pub use file::FileStorage;
+pub use sqlite::SqliteStorage;
+
+use crate::certificate::Certificate;
+use acme_common::crypto::{KeyPair, X509Certificate};
+use acme_common::error::Error;
+
+/// Abstracts over where account keys, certificate keys and certificates are persisted.
+pub trait Storage {
+    fn get_account_keypair(&self, cert: &Certificate) -> Result<KeyPair, Error>;
+
+    fn set_account_keypair(&self, cert: &Certificate, key_pair: &KeyPair) -> Result<(), Error>;
+
+    fn get_keypair(&self, cert: &Certificate) -> Result<KeyPair, Error>;
+
+    fn set_keypair(&self, cert: &Certificate, key_pair: &KeyPair) -> Result<(), Error>;
+
+    fn get_certificate(&self, cert: &Certificate) -> Result<X509Certificate, Error>;
+
+    fn write_certificate(&self, cert: &Certificate, data: &[u8]) -> Result<(), Error>;
+
+    fn account_files_exists(&self, cert: &Certificate) -> bool;
+
+    fn certificate_files_exists(&self, cert: &Certificate) -> bool;
+}
+
+/// Instantiates the storage backend named `name`, using `path` as its location.
+pub fn get_storage(name: &str, path: &str) -> Result<Box<dyn Storage>, Error> {
+    match name {
+        "file" => Ok(Box::new(FileStorage)),
+        "sqlite" => Ok(Box::new(SqliteStorage::new(path)?)),
+        _ => Err(format!("{}: unknown storage backend", name).into()),
+    }
+}